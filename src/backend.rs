@@ -0,0 +1,67 @@
+use std::cell::Cell;
+use std::io;
+use std::mem;
+use std::sync::Arc;
+use libc::c_void;
+
+/// A shared-memory mapping backend for Mumble Link.
+///
+/// The OS-specific backends in `windows.rs`/`unix.rs` (used internally as
+/// `imp::Map`) are the default, selected at compile time for the host
+/// platform. Implement this trait to substitute another mapping, such as an
+/// in-process buffer for tests -- see `MemoryBackend`. The OS backend is
+/// compiled in regardless, so building the crate still needs
+/// `OpenFileMappingW` (Windows) or `shm_open` (elsewhere).
+pub trait MappingBackend: Sized {
+    /// Open (or create) a mapping at least `size` bytes long, sized to hold
+    /// a `LinkedMem`.
+    fn open(size: usize) -> io::Result<Self>;
+
+    /// A pointer to the start of the mapping, valid for the `size` passed to
+    /// `open` for as long as `self` is alive. Any cleanup needed on mapping
+    /// close should happen in `Drop`.
+    fn ptr(&self) -> *mut c_void;
+}
+
+/// An in-memory `MappingBackend` backed by a heap allocation shared by
+/// reference count.
+///
+/// This backend never fails to open and is never observed by an actual
+/// instance of Mumble; it exists so that `MumbleLink`/`SharedLink`/
+/// `MumbleLinkReader` logic can be exercised in unit tests and other
+/// headless environments without a running Mumble or platform shared-memory
+/// support. Use `handle()` to get a second `MemoryBackend` pointing at the
+/// same simulated mapping, mirroring how two processes can open the same
+/// named OS mapping -- for example to drive a `MumbleLink`/`SharedLink`
+/// writer and a `MumbleLinkReader` against each other in a test.
+pub struct MemoryBackend {
+    buf: Arc<[Cell<u64>]>,
+}
+
+impl MemoryBackend {
+    /// Create a second handle onto the same simulated shared memory as
+    /// `self`.
+    pub fn handle(&self) -> MemoryBackend {
+        MemoryBackend { buf: Arc::clone(&self.buf) }
+    }
+}
+
+impl MappingBackend for MemoryBackend {
+    fn open(size: usize) -> io::Result<MemoryBackend> {
+        // Back the allocation with `Cell<u64>` rather than `Cell<u8>`. A
+        // `Vec<u8>`'s allocator layout is only guaranteed 1-byte aligned,
+        // but `ptr()` below is handed straight to `lib.rs` and cast to
+        // `*mut LinkedMem`, which `write_volatile`/`read_volatile` require
+        // to be properly aligned; `align_of::<u64>()` (8) safely covers
+        // `align_of::<LinkedMem>()` (4).
+        let elems = size.div_ceil(mem::size_of::<u64>());
+        let buf: Vec<Cell<u64>> = (0..elems).map(|_| Cell::new(0u64)).collect();
+        Ok(MemoryBackend {
+            buf: Arc::from(buf.into_boxed_slice()),
+        })
+    }
+
+    fn ptr(&self) -> *mut c_void {
+        self.buf.as_ptr() as *mut Cell<u64> as *mut u64 as *mut c_void
+    }
+}
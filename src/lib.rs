@@ -5,10 +5,16 @@
 //!
 //! Connect to Mumble link with `MumbleLink::new()`, set the context or player
 //! identity as needed, and call `update()` every frame with the position data.
+//!
+//! `MumbleLink`, `SharedLink`, and `MumbleLinkReader` are generic over a
+//! [`MappingBackend`](trait.MappingBackend.html), defaulting to the host
+//! platform's shared memory implementation. Use `with_backend()` to plug in
+//! a different backend, such as `MemoryBackend` for tests.
 
 extern crate libc;
 
 use std::{io, ptr, mem};
+use std::sync::atomic;
 use libc::{c_float, wchar_t};
 
 macro_rules! wide {
@@ -21,6 +27,9 @@ macro_rules! wide {
 #[cfg_attr(not(windows), path="unix.rs")]
 mod imp;
 
+mod backend;
+pub use backend::{MappingBackend, MemoryBackend};
+
 /// A position in three-dimensional space.
 ///
 /// The vectors are in a left-handed coordinate system: X positive towards
@@ -51,6 +60,12 @@ impl Default for Position {
     }
 }
 
+// `#[repr(C)]` pins LinkedMem's field order and layout to match the offsets
+// Mumble expects in the "MumbleLink" shared memory; without it, Rust is free
+// to reorder fields and the layout only happened to line up by accident. The
+// assertions below catch any future field reorder or size change at compile
+// time rather than producing silently garbled position/identity data.
+#[repr(C)]
 #[derive(Copy)]
 struct LinkedMem {
     ui_version: u32,
@@ -64,6 +79,29 @@ struct LinkedMem {
     description: [wchar_t; 2048],
 }
 
+const _: () = {
+    let mut offset = 0usize;
+    assert!(mem::offset_of!(LinkedMem, ui_version) == offset);
+    offset += mem::size_of::<u32>();
+    assert!(mem::offset_of!(LinkedMem, ui_tick) == offset);
+    offset += mem::size_of::<u32>();
+    assert!(mem::offset_of!(LinkedMem, avatar) == offset);
+    offset += mem::size_of::<Position>();
+    assert!(mem::offset_of!(LinkedMem, name) == offset);
+    offset += mem::size_of::<[wchar_t; 256]>();
+    assert!(mem::offset_of!(LinkedMem, camera) == offset);
+    offset += mem::size_of::<Position>();
+    assert!(mem::offset_of!(LinkedMem, identity) == offset);
+    offset += mem::size_of::<[wchar_t; 256]>();
+    assert!(mem::offset_of!(LinkedMem, context_len) == offset);
+    offset += mem::size_of::<u32>();
+    assert!(mem::offset_of!(LinkedMem, context) == offset);
+    offset += mem::size_of::<[u8; 256]>();
+    assert!(mem::offset_of!(LinkedMem, description) == offset);
+    offset += mem::size_of::<[wchar_t; 2048]>();
+    assert!(mem::size_of::<LinkedMem>() == offset);
+};
+
 impl Clone for LinkedMem {
     fn clone(&self) -> Self { *self }
 }
@@ -102,24 +140,97 @@ impl LinkedMem {
         self.avatar = avatar;
         self.camera = camera;
     }
+
+    /// Write this value into the shared-memory mapping at `dst`, field by
+    /// field through `addr_of_mut!`-derived raw pointers. This avoids ever
+    /// forming a `&mut LinkedMem` reference over memory
+    /// that the Mumble process may be concurrently reading, which would be
+    /// undefined behavior to alias as a full Rust reference.
+    ///
+    /// `ui_version` and `ui_tick` -- in that order -- are written last, and
+    /// `read_consistent`'s seqlock treats `ui_tick` as the sole sequence
+    /// number. A reader must never be able to observe a bumped `ui_tick`
+    /// paired with stale data, so every other field is written first, with
+    /// a release fence separating the data writes from the two header
+    /// writes.
+    unsafe fn write_volatile_to(&self, dst: *mut LinkedMem) {
+        ptr::write_volatile(ptr::addr_of_mut!((*dst).avatar), self.avatar);
+        ptr::write_volatile(ptr::addr_of_mut!((*dst).name), self.name);
+        ptr::write_volatile(ptr::addr_of_mut!((*dst).camera), self.camera);
+        ptr::write_volatile(ptr::addr_of_mut!((*dst).identity), self.identity);
+        ptr::write_volatile(ptr::addr_of_mut!((*dst).context_len), self.context_len);
+        ptr::write_volatile(ptr::addr_of_mut!((*dst).context), self.context);
+        ptr::write_volatile(ptr::addr_of_mut!((*dst).description), self.description);
+        atomic::fence(atomic::Ordering::Release);
+        ptr::write_volatile(ptr::addr_of_mut!((*dst).ui_version), self.ui_version);
+        ptr::write_volatile(ptr::addr_of_mut!((*dst).ui_tick), self.ui_tick);
+    }
+
+    /// Read just `ui_version` and `ui_tick` out of the shared memory at
+    /// `ptr`, through `addr_of!`-derived raw pointers, without copying out
+    /// the full (multi-kilobyte) `LinkedMem` merely to inspect two `u32`s.
+    unsafe fn read_header(ptr: *const LinkedMem) -> (u32, u32) {
+        (
+            ptr::read_volatile(ptr::addr_of!((*ptr).ui_version)),
+            ptr::read_volatile(ptr::addr_of!((*ptr).ui_tick)),
+        )
+    }
+
+    /// Read a best-effort consistent snapshot of the `LinkedMem` at `ptr`.
+    ///
+    /// Mumble increments `ui_tick` exactly once per write, which makes it a
+    /// natural seqlock sequence number: `ui_tick` is read immediately before
+    /// and immediately after copying the struct out, and the copy is retried
+    /// if the two values differ, since a write finished in between. A write
+    /// still in progress at the second read (its data partly stored, its
+    /// `ui_tick` not yet) is not detected, and that copy is returned. The
+    /// retry is bounded; if the two values never match (the writer is
+    /// updating unusually fast) the last snapshot taken is returned anyway.
+    unsafe fn read_consistent(ptr: *const LinkedMem) -> LinkedMem {
+        let mut mem = ptr::read_volatile(ptr);
+        for _ in 0..Self::SEQLOCK_RETRIES {
+            let t0 = ptr::read_volatile(ptr::addr_of!((*ptr).ui_tick));
+            atomic::fence(atomic::Ordering::Acquire);
+            mem = ptr::read_volatile(ptr);
+            atomic::fence(atomic::Ordering::Acquire);
+            let t1 = ptr::read_volatile(ptr::addr_of!((*ptr).ui_tick));
+            if t0 == t1 {
+                break;
+            }
+        }
+        mem
+    }
+
+    const SEQLOCK_RETRIES: u32 = 4;
 }
 
 /// An active Mumble link connection.
-pub struct MumbleLink {
-    map: imp::Map,
+pub struct MumbleLink<B: MappingBackend = imp::Map> {
+    map: B,
     local: LinkedMem,
 }
 
-impl MumbleLink {
+impl MumbleLink<imp::Map> {
     /// Attempt to open the Mumble link, providing the specified application
     /// name and description.
     ///
     /// Opening the link will fail if Mumble is not running. If another
     /// application is also using Mumble link, its data may be overwritten or
     /// conflict with this link. To avoid this, use `SharedLink`.
-    pub fn new(name: &str, description: &str) -> io::Result<MumbleLink> {
+    pub fn new(name: &str, description: &str) -> io::Result<MumbleLink<imp::Map>> {
+        MumbleLink::with_backend(name, description)
+    }
+}
+
+impl<B: MappingBackend> MumbleLink<B> {
+    /// Attempt to open the Mumble link using a specific `MappingBackend`,
+    /// providing the specified application name and description.
+    ///
+    /// This is the generalization of `new()` for backends other than the
+    /// default OS shared memory, such as `MemoryBackend` in tests.
+    pub fn with_backend(name: &str, description: &str) -> io::Result<MumbleLink<B>> {
         Ok(MumbleLink {
-            map: try!(imp::Map::new(std::mem::size_of::<LinkedMem>())),
+            map: try!(B::open(std::mem::size_of::<LinkedMem>())),
             local: LinkedMem::new(name, description),
         })
     }
@@ -171,18 +282,18 @@ impl MumbleLink {
     pub fn update(&mut self, avatar: Position, camera: Position) {
         self.local.update(avatar, camera);
         unsafe {
-            ptr::write_volatile(self.map.ptr as *mut LinkedMem, self.local);
+            self.local.write_volatile_to(self.map.ptr() as *mut LinkedMem);
         }
     }
 }
 
-unsafe impl Send for MumbleLink {}
+unsafe impl<B: MappingBackend + Send> Send for MumbleLink<B> {}
 
-impl Drop for MumbleLink {
+impl<B: MappingBackend> Drop for MumbleLink<B> {
     fn drop(&mut self) {
         unsafe {
             // zero the linked memory
-            ptr::write_volatile(self.map.ptr as *mut LinkedMem, mem::zeroed());
+            ptr::write_volatile(self.map.ptr() as *mut LinkedMem, mem::zeroed());
         }
     }
 }
@@ -193,15 +304,26 @@ impl Drop for MumbleLink {
 /// or another application is writing to the link. If this happens, `update()`
 /// will retry opening the link on a regular basis, succeeding if Mumble is
 /// started or the other application stops using the link.
-pub struct SharedLink {
-    inner: Inner,
+pub struct SharedLink<B: MappingBackend = imp::Map> {
+    inner: Inner<B>,
     local: LinkedMem,
 }
 
-impl SharedLink {
+impl SharedLink<imp::Map> {
     /// Open the Mumble link, providing the specified application name and
     /// description.
-    pub fn new(name: &str, description: &str) -> SharedLink {
+    pub fn new(name: &str, description: &str) -> SharedLink<imp::Map> {
+        SharedLink::with_backend(name, description)
+    }
+}
+
+impl<B: MappingBackend> SharedLink<B> {
+    /// Open the Mumble link using a specific `MappingBackend`, providing the
+    /// specified application name and description.
+    ///
+    /// This is the generalization of `new()` for backends other than the
+    /// default OS shared memory, such as `MemoryBackend` in tests.
+    pub fn with_backend(name: &str, description: &str) -> SharedLink<B> {
         SharedLink {
             inner: Inner::open(),
             local: LinkedMem::new(name, description),
@@ -259,11 +381,13 @@ impl SharedLink {
             self.inner = match mem::replace(&mut self.inner, Inner::Unset) {
                 Inner::Closed(_) => Inner::open(),
                 Inner::InUse(map, last_tick) => {
-                    let previous = unsafe { ptr::read_volatile(map.ptr as *mut LinkedMem) };
-                    if previous.ui_version == 0 || last_tick == previous.ui_tick {
+                    let (ui_version, ui_tick) = unsafe {
+                        LinkedMem::read_header(map.ptr() as *const LinkedMem)
+                    };
+                    if ui_version == 0 || last_tick == ui_tick {
                         Inner::Active(map)
                     } else {
-                        Inner::InUse(map, previous.ui_tick)
+                        Inner::InUse(map, ui_tick)
                     }
                 }
                 Inner::Active(map) => Inner::Active(map),
@@ -274,7 +398,7 @@ impl SharedLink {
         // If the link is active, write to it
         if let Inner::Active(ref mut map) = self.inner {
             unsafe {
-                ptr::write_volatile(map.ptr as *mut LinkedMem, self.local);
+                self.local.write_volatile_to(map.ptr() as *mut LinkedMem);
             }
         }
     }
@@ -284,7 +408,7 @@ impl SharedLink {
         match self.inner {
             Inner::Closed(ref err) => Status::Closed(err),
             Inner::InUse(ref map, _) => {
-                let previous = unsafe { ptr::read_volatile(map.ptr as *mut LinkedMem) };
+                let previous = unsafe { LinkedMem::read_consistent(map.ptr() as *const LinkedMem) };
                 Status::InUse {
                     name: imp::read(&previous.name),
                     description: imp::read(&previous.description)
@@ -302,36 +426,38 @@ impl SharedLink {
     pub fn deactivate(&mut self) {
         if let Inner::Active(ref mut map) = self.inner {
             unsafe {
-                ptr::write_volatile(map.ptr as *mut LinkedMem, mem::zeroed());
+                ptr::write_volatile(map.ptr() as *mut LinkedMem, mem::zeroed());
             }
         }
         self.inner = Inner::Closed(io::Error::new(io::ErrorKind::Other, "Manually closed"));
     }
 }
 
-unsafe impl Send for SharedLink {}
+unsafe impl<B: MappingBackend + Send> Send for SharedLink<B> {}
 
-impl Drop for SharedLink {
+impl<B: MappingBackend> Drop for SharedLink<B> {
     fn drop(&mut self) {
         self.deactivate();
     }
 }
 
-enum Inner {
+enum Inner<B: MappingBackend> {
     Unset,
     Closed(io::Error),
-    InUse(imp::Map, u32),
-    Active(imp::Map),
+    InUse(B, u32),
+    Active(B),
 }
 
-impl Inner {
-    fn open() -> Inner {
-        match imp::Map::new(std::mem::size_of::<LinkedMem>()) {
+impl<B: MappingBackend> Inner<B> {
+    fn open() -> Inner<B> {
+        match B::open(std::mem::size_of::<LinkedMem>()) {
             Err(err) => Inner::Closed(err),
             Ok(map) => {
-                let previous = unsafe { ptr::read_volatile(map.ptr as *mut LinkedMem) };
-                if previous.ui_version != 0 {
-                    Inner::InUse(map, previous.ui_tick)
+                let (ui_version, ui_tick) = unsafe {
+                    LinkedMem::read_header(map.ptr() as *const LinkedMem)
+                };
+                if ui_version != 0 {
+                    Inner::InUse(map, ui_tick)
                 } else {
                     Inner::Active(map)
                 }
@@ -340,6 +466,100 @@ impl Inner {
     }
 }
 
+/// A snapshot of another application's live Mumble Link data, as seen by a
+/// `MumbleLinkReader`.
+pub struct LinkSnapshot {
+    /// The position of the linked character.
+    pub avatar: Position,
+    /// The position of the camera.
+    pub camera: Position,
+    /// The name of the application which owns the link.
+    pub name: String,
+    /// The identity of the linked player, as set by the owning application.
+    pub identity: String,
+    /// The raw context bytes set by the owning application.
+    pub context: Vec<u8>,
+    /// The tick counter, incremented by the owning application once per
+    /// `update()`.
+    pub ui_tick: u32,
+}
+
+/// A read-only view of another application's Mumble Link data.
+///
+/// Unlike `MumbleLink` and `SharedLink`, which write positional data,
+/// `MumbleLinkReader` observes whatever is already being written to the
+/// `"MumbleLink"` shared memory by another process. This is useful for
+/// overlay or companion tools -- radar displays, squad trackers, and the
+/// like -- that want to consume position data without owning the link
+/// themselves.
+pub struct MumbleLinkReader<B: MappingBackend = imp::Map> {
+    map: B,
+    last_tick: Option<u32>,
+}
+
+impl MumbleLinkReader<imp::Map> {
+    /// Open the Mumble link for reading.
+    ///
+    /// This fails only if the `"MumbleLink"` shared memory cannot be
+    /// opened, typically because Mumble is not running. It succeeds even if
+    /// no application is currently writing to the link; use `read()` to
+    /// find out whether the link is active.
+    pub fn new() -> io::Result<MumbleLinkReader<imp::Map>> {
+        MumbleLinkReader::with_backend()
+    }
+}
+
+impl<B: MappingBackend> MumbleLinkReader<B> {
+    /// Open the Mumble link for reading using a specific `MappingBackend`.
+    ///
+    /// This is the generalization of `new()` for backends other than the
+    /// default OS shared memory, such as `MemoryBackend` in tests.
+    pub fn with_backend() -> io::Result<MumbleLinkReader<B>> {
+        Ok(MumbleLinkReader {
+            map: try!(B::open(mem::size_of::<LinkedMem>())),
+            last_tick: None,
+        })
+    }
+
+    /// Read the current contents of the link.
+    ///
+    /// Returns `None` if no application is writing to the link
+    /// (`ui_version` is zero) or if `ui_tick` has not advanced since the
+    /// last call, meaning whatever was writing has gone idle. Otherwise
+    /// returns a snapshot of the data currently written by whatever
+    /// application owns the link.
+    pub fn read(&mut self) -> Option<LinkSnapshot> {
+        let mem = unsafe { LinkedMem::read_consistent(self.map.ptr() as *const LinkedMem) };
+        if mem.ui_version == 0 {
+            self.last_tick = None;
+            return None;
+        }
+
+        let advanced = self.last_tick != Some(mem.ui_tick);
+        self.last_tick = Some(mem.ui_tick);
+        if !advanced {
+            return None;
+        }
+
+        // `context_len` comes from another process's shared memory and is
+        // not validated by this crate's own `set_context` clamp, so a
+        // buggy or hostile writer could put any value in it; clamp before
+        // slicing to avoid panicking on out-of-range data.
+        let context_len = (mem.context_len as usize).min(mem.context.len());
+
+        Some(LinkSnapshot {
+            avatar: mem.avatar,
+            camera: mem.camera,
+            name: imp::read(&mem.name),
+            identity: imp::read(&mem.identity),
+            context: mem.context[..context_len].to_vec(),
+            ui_tick: mem.ui_tick,
+        })
+    }
+}
+
+unsafe impl<B: MappingBackend + Send> Send for MumbleLinkReader<B> {}
+
 /// The status of a `SharedLink`.
 #[derive(Debug)]
 pub enum Status<'a> {
@@ -377,3 +597,59 @@ fn test_wide() {
 
     assert_eq!("BarFoo", imp::read(&wide!(B a r F o o)));
 }
+
+#[test]
+fn test_memory_backend() {
+    let mut link = MumbleLink::<MemoryBackend>::with_backend("Test", "test.").unwrap();
+    link.set_identity("Hero");
+    link.update(Position::default(), Position::default());
+}
+
+#[test]
+fn test_shared_link_memory_backend() {
+    let mut link = SharedLink::<MemoryBackend>::with_backend("Test", "test.");
+    match link.status() {
+        Status::Active => {}
+        other => panic!("expected Active, got {:?}", other),
+    }
+
+    link.update(Position::default(), Position::default());
+    link.deactivate();
+
+    match link.status() {
+        Status::Closed(_) => {}
+        other => panic!("expected Closed, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_memory_backend_writer_and_reader() {
+    let writer_map = MemoryBackend::open(mem::size_of::<LinkedMem>()).unwrap();
+    let reader_map = writer_map.handle();
+
+    let mut writer = MumbleLink {
+        map: writer_map,
+        local: LinkedMem::new("Test", "test."),
+    };
+    let mut reader = MumbleLinkReader {
+        map: reader_map,
+        last_tick: None,
+    };
+
+    // Nothing has been written to the shared mapping yet.
+    assert!(reader.read().is_none());
+
+    writer.set_identity("Hero");
+    writer.update(Position::default(), Position::default());
+
+    let snapshot = reader.read().expect("reader should observe the writer's update");
+    assert_eq!(snapshot.identity, "Hero");
+    assert_eq!(snapshot.ui_tick, 1);
+
+    // No further update happened, so ui_tick hasn't advanced.
+    assert!(reader.read().is_none());
+
+    writer.update(Position::default(), Position::default());
+    let snapshot = reader.read().expect("reader should observe the second update");
+    assert_eq!(snapshot.ui_tick, 2);
+}
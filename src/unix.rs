@@ -1,6 +1,7 @@
 use std::{io, ptr};
 use std::ffi::CString;
 use libc::{self, wchar_t};
+use super::backend::MappingBackend;
 
 pub fn copy(dest: &mut [wchar_t], src: &str) {
     if dest.is_empty() { return }
@@ -64,3 +65,15 @@ impl Drop for Map {
         }
     }
 }
+
+unsafe impl Send for Map {}
+
+impl MappingBackend for Map {
+    fn open(size: usize) -> io::Result<Map> {
+        Map::new(size)
+    }
+
+    fn ptr(&self) -> *mut libc::c_void {
+        self.ptr
+    }
+}
@@ -1,6 +1,7 @@
 use std::io;
 use libc::{c_void, wchar_t};
 use winapi::um::{winnt, memoryapi, handleapi};
+use super::backend::MappingBackend;
 
 pub fn copy(dest: &mut [wchar_t], src: &str) {
     if dest.is_empty() { return }
@@ -60,3 +61,15 @@ impl Drop for Map {
         }
     }
 }
+
+unsafe impl Send for Map {}
+
+impl MappingBackend for Map {
+    fn open(size: usize) -> io::Result<Map> {
+        Map::new(size)
+    }
+
+    fn ptr(&self) -> *mut c_void {
+        self.ptr
+    }
+}